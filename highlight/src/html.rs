@@ -0,0 +1,243 @@
+use crate::{escape, Error, Highlight, HighlightEvent};
+use std::fmt::Write;
+
+/// Precomputes a `class="..."` attribute string for each configured highlight name, for
+/// callers that just want a CSS class per capture name rather than hand-writing an
+/// `attribute_callback`. Index the result with a `Highlight`'s `.0` (e.g.
+/// `|h: Highlight| attrs[h.0].as_str()`) to use it as `HtmlRenderer`'s callback — except
+/// for `Highlight::CARRIAGE_RETURN`, which isn't a valid index into this `Vec`; use
+/// `class_attribute_callback` instead if the rendered stream may contain CRLF line
+/// endings.
+pub fn class_attributes(names: &[String]) -> Vec<String> {
+    names.iter().map(|name| format!("class=\"{}\"", name)).collect()
+}
+
+/// Wraps `class_attributes`'s output into an `attribute_callback`, special-casing
+/// `Highlight::CARRIAGE_RETURN` so carriage-return spans get `carriage_return_class`
+/// instead of indexing `attrs` with the reserved highlight's out-of-range `.0`.
+pub fn class_attribute_callback<'a>(
+    attrs: &'a [String],
+    carriage_return_class: &'a str,
+) -> impl Fn(Highlight) -> &'a str {
+    move |h| {
+        if h == Highlight::CARRIAGE_RETURN {
+            carriage_return_class
+        } else {
+            attrs[h.0].as_str()
+        }
+    }
+}
+
+/// Renders a `HighlightEvent` stream to HTML, wrapping each highlighted span in a
+/// `<span>` tag whose attributes come from a caller-supplied `attribute_callback`.
+/// This mirrors the `HtmlRenderer` offered by tree-sitter-highlight, writing into a
+/// single growing buffer (rather than a fresh `String` per line) and recording each
+/// line's byte range within it, so large documents don't pay a per-line allocation.
+///
+/// A stack of currently-open highlights is maintained so that every embedded newline
+/// closes and reopens all of them, keeping each rendered line's markup self-contained
+/// (valid on its own, with no spans left dangling open across line boundaries).
+pub struct HtmlRenderer<'a, F: Fn(Highlight) -> &'a str> {
+    buffer: String,
+    // The byte range of each finished line within `buffer`, alongside the highlights
+    // that were already open at the start of that line.
+    line_ranges: Vec<(usize, usize)>,
+    line_start_highlights: Vec<Vec<Highlight>>,
+    current_line_start: usize,
+    current_line_start_highlights: Vec<Highlight>,
+    open_highlights: Vec<Highlight>,
+    attribute_callback: F,
+}
+
+impl<'a, F> HtmlRenderer<'a, F>
+where
+    F: Fn(Highlight) -> &'a str,
+{
+    pub fn new(attribute_callback: F) -> Self {
+        Self::with_capacity(attribute_callback, 0)
+    }
+
+    /// Like `new`, but pre-reserves `capacity` bytes on the output buffer, so writing a
+    /// large document doesn't immediately trigger a reallocation.
+    pub fn with_capacity(attribute_callback: F, capacity: usize) -> Self {
+        HtmlRenderer {
+            buffer: String::with_capacity(capacity),
+            line_ranges: Vec::new(),
+            line_start_highlights: Vec::new(),
+            current_line_start: 0,
+            current_line_start_highlights: Vec::new(),
+            open_highlights: Vec::new(),
+            attribute_callback,
+        }
+    }
+
+    /// Consumes a `HighlightEvent` stream and returns the concatenated HTML for the
+    /// whole document.
+    pub fn render_to_string<I>(mut self, events: I) -> Result<String, Error>
+    where
+        I: IntoIterator<Item = Result<HighlightEvent<'a>, Error>>,
+    {
+        self.run(events)?;
+        Ok(self.buffer)
+    }
+
+    /// Consumes a `HighlightEvent` stream and returns each line's HTML alongside the
+    /// highlights that were already open at the start of that line, so a caller that
+    /// re-renders a single changed line knows which spans to reopen around it.
+    pub fn render_lines<I>(mut self, events: I) -> Result<Vec<(String, Vec<Highlight>)>, Error>
+    where
+        I: IntoIterator<Item = Result<HighlightEvent<'a>, Error>>,
+    {
+        self.run(events)?;
+        let buffer = self.buffer;
+        Ok(self
+            .line_ranges
+            .into_iter()
+            .map(|(start, end)| buffer[start..end].to_string())
+            .zip(self.line_start_highlights)
+            .collect())
+    }
+
+    /// Consumes a `HighlightEvent` stream and returns one concatenated HTML buffer
+    /// alongside each line's byte range within it, so a caller can map rendered output
+    /// back to source lines without holding each line separately. `expected_capacity` is
+    /// reserved on the buffer up front (e.g. the source's byte length plus some slack for
+    /// markup) to avoid reallocation while writing.
+    pub fn render<I>(mut self, events: I, expected_capacity: usize) -> Result<(String, Vec<(usize, usize)>), Error>
+    where
+        I: IntoIterator<Item = Result<HighlightEvent<'a>, Error>>,
+    {
+        self.buffer.reserve(expected_capacity);
+        self.run(events)?;
+        Ok((self.buffer, self.line_ranges))
+    }
+
+    fn run<I>(&mut self, events: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Result<HighlightEvent<'a>, Error>>,
+    {
+        for event in events {
+            match event? {
+                HighlightEvent::HighlightStart(h) => self.start_scope(h),
+                HighlightEvent::HighlightEnd => self.end_scope(),
+                HighlightEvent::Source(src) => self.add_text(src.as_ref()),
+            }
+        }
+        if self.buffer.len() > self.current_line_start || !self.open_highlights.is_empty() {
+            self.finish_line();
+        }
+        Ok(())
+    }
+
+    fn write_open_tag(&mut self, h: Highlight) {
+        write!(&mut self.buffer, "<span {}>", (self.attribute_callback)(h)).unwrap();
+    }
+
+    fn write_close_tag(&mut self) {
+        write!(&mut self.buffer, "</span>").unwrap();
+    }
+
+    fn start_scope(&mut self, h: Highlight) {
+        self.open_highlights.push(h);
+        self.write_open_tag(h);
+    }
+
+    fn end_scope(&mut self) {
+        self.open_highlights.pop();
+        self.write_close_tag();
+    }
+
+    fn finish_line(&mut self) {
+        self.buffer.push('\n');
+        let start_highlights = std::mem::take(&mut self.current_line_start_highlights);
+        self.line_ranges.push((self.current_line_start, self.buffer.len()));
+        self.line_start_highlights.push(start_highlights);
+        self.current_line_start = self.buffer.len();
+        self.current_line_start_highlights = self.open_highlights.clone();
+    }
+
+    fn add_text(&mut self, src: &str) {
+        let mut multiline = false;
+        for line in src.split('\n') {
+            // Rather than silently trimming a trailing `\r` (as a bare `\n`-split would
+            // leave it), render it as its own empty span so CRLF line endings round-trip
+            // visibly in the output instead of looking identical to LF endings.
+            let (line, has_carriage_return) = match line.strip_suffix('\r') {
+                Some(stripped) => (stripped, true),
+                None => (line, false),
+            };
+            if multiline {
+                for _ in 0..self.open_highlights.len() {
+                    self.write_close_tag();
+                }
+                self.finish_line();
+                for h in self.open_highlights.clone() {
+                    self.write_open_tag(h);
+                }
+            }
+            write!(&mut self.buffer, "{}", escape::Escape(line)).unwrap();
+            if has_carriage_return {
+                self.write_open_tag(Highlight::CARRIAGE_RETURN);
+                self.write_close_tag();
+            }
+            multiline = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carriage_returns_round_trip_through_a_custom_attribute_callback() {
+        let attrs = vec!["class=\"kw\"".to_string()];
+        let callback = class_attribute_callback(&attrs, "class=\"cr\"");
+
+        let events: Vec<Result<HighlightEvent, Error>> = vec![
+            Ok(HighlightEvent::HighlightStart(Highlight(0))),
+            Ok(HighlightEvent::Source("if".into())),
+            Ok(HighlightEvent::HighlightEnd),
+            Ok(HighlightEvent::Source("\r\nelse".into())),
+        ];
+
+        let (buffer, line_ranges) = HtmlRenderer::new(callback).render(events, 0).unwrap();
+
+        assert_eq!(
+            buffer,
+            "<span class=\"kw\">if</span><span class=\"cr\"></span>\nelse\n",
+        );
+        assert_eq!(line_ranges.len(), 2, "the CRLF should start a new line");
+        let (start, end) = line_ranges[0];
+        assert_eq!(
+            &buffer[start..end],
+            "<span class=\"kw\">if</span><span class=\"cr\"></span>\n",
+        );
+        let (start, end) = line_ranges[1];
+        assert_eq!(&buffer[start..end], "else\n");
+    }
+
+    #[test]
+    fn finish_line_reopens_highlights_spanning_a_line_break() {
+        let attrs = vec!["class=\"str\"".to_string()];
+        let callback = class_attribute_callback(&attrs, "class=\"cr\"");
+
+        let events: Vec<Result<HighlightEvent, Error>> = vec![
+            Ok(HighlightEvent::HighlightStart(Highlight(0))),
+            Ok(HighlightEvent::Source("a\nb".into())),
+            Ok(HighlightEvent::HighlightEnd),
+        ];
+
+        let lines = HtmlRenderer::new(callback).render_lines(events).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].0, "<span class=\"str\">a</span>\n");
+        assert_eq!(lines[0].1, Vec::<Highlight>::new());
+        assert_eq!(lines[1].0, "<span class=\"str\">b</span>\n");
+        assert_eq!(
+            lines[1].1,
+            vec![Highlight(0)],
+            "the second line should know the `str` highlight was already open when it started"
+        );
+    }
+}
@@ -1,23 +1,45 @@
 pub mod c_lib;
 mod escape;
 mod cow;
+pub mod html;
 
 pub use c_lib as c;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::Deserialize;
 use serde_derive::*;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self, Write};
 use std::mem::transmute;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{cmp, str, usize};
-use tree_sitter::{Language, Node, Parser, Point, PropertySheet, Range, Tree, TreePropertyCursor, NodeSource};
+use slotmap::{new_key_type, HopSlotMap};
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, PropertySheet, Range, Tree, TreePropertyCursor, NodeSource};
 use std::borrow::Cow;
 
+new_key_type! {
+    /// Identifies a layer within a highlighter's layer pool. Stable across highlighting
+    /// passes so an injection layer that an edit didn't touch can be looked up and
+    /// reused instead of reparsed.
+    pub struct LayerId;
+}
+
+// The key used to recognize "the same injection" across passes and across repeated
+// matches within a single pass: the language it's written in, plus the byte ranges of
+// the source it covers.
+fn layer_key(language_string: &str, ranges: &[Range]) -> (String, Vec<(usize, usize)>) {
+    (
+        language_string.to_string(),
+        ranges.iter().map(|r| (r.start_byte, r.end_byte)).collect(),
+    )
+}
+
 const CANCELLATION_CHECK_INTERVAL: usize = 100;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     Cancelled,
     InvalidLanguage,
+    Timeout,
     Unknown,
 }
 
@@ -46,49 +68,91 @@ struct Injection {
     language: InjectionLanguage,
     content: Vec<TreeStep>,
     includes_children: bool,
+    // When set, every content node matched for this injection throughout the parent
+    // layer is merged into a single layer for the language, instead of each match
+    // spawning its own layer. See `injection-combined` in the property sheet format.
+    combined: bool,
 }
 
 #[derive(Debug)]
 pub struct Properties {
-    highlight: Option<Highlight>,
-    highlight_nonlocal: Option<Highlight>,
+    highlight: Option<String>,
+    highlight_nonlocal: Option<String>,
     injections: Vec<Injection>,
     local_scope: Option<bool>,
     local_definition: bool,
     local_reference: bool,
 }
 
+/// An index into the caller-supplied list of recognized highlight names (the
+/// `recognized_names` parameter of `Highlighter::new`). The property sheet's
+/// `highlight` values are plain scope-name strings like `variable.parameter`, resolved
+/// to a `Highlight` by `resolve_highlight` at highlighting time, so callers can use
+/// whatever taxonomy their theme or grammar expects instead of a fixed, closed set.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[repr(u16)]
-pub enum Highlight {
-    Attribute,
-    Comment,
-    Constant,
-    ConstantBuiltin,
-    Constructor,
-    ConstructorBuiltin,
-    Embedded,
-    Escape,
-    Function,
-    FunctionBuiltin,
-    Keyword,
-    Number,
-    Operator,
-    Property,
-    PropertyBuiltin,
-    Punctuation,
-    PunctuationBracket,
-    PunctuationDelimiter,
-    PunctuationSpecial,
-    String,
-    StringSpecial,
-    Tag,
-    Type,
-    TypeBuiltin,
-    Variable,
-    VariableBuiltin,
-    VariableParameter,
-    Unknown,
+pub struct Highlight(pub usize);
+
+impl Highlight {
+    /// A reserved value passed to an `attribute_callback` for the empty span that
+    /// `html::HtmlRenderer` wraps a CRLF line ending's carriage return in, instead of a
+    /// real index into a caller's `recognized_names`. The highlighter itself never
+    /// produces this value; only the HTML renderer does, for that one synthetic span.
+    pub const CARRIAGE_RETURN: Highlight = Highlight(usize::MAX);
+}
+
+/// Resolves a scope name from a property sheet (e.g. `variable.parameter`) to its index
+/// in `names`, trying progressively shorter dot-separated prefixes until one matches
+/// (e.g. falling back to `variable` if `variable.parameter` isn't configured). Exposed so
+/// callers can resolve capture names against a configured taxonomy (e.g. to validate a
+/// theme or a grammar's capture list) without driving a full highlighting pass.
+pub fn resolve_highlight(names: &[String], scope: &str) -> Option<Highlight> {
+    let mut end = scope.len();
+    loop {
+        if let Some(index) = names.iter().position(|name| name == &scope[..end]) {
+            return Some(Highlight(index));
+        }
+        match scope[..end].rfind('.') {
+            Some(dot) => end = dot,
+            None => return None,
+        }
+    }
+}
+
+/// Resolves a raw injected language string (e.g. a Markdown fenced code block's info
+/// string, or an HTML `<script type="...">` attribute) to the name that was actually
+/// registered with the grammar. Tries each of `injection_language_regexes` in order
+/// against `raw` lowercased, so a lowercase pattern matches `raw` in any case (a pattern
+/// with uppercase letters in it never matches), before falling back to treating `raw`
+/// as the literal name. Exposed
+/// alongside `resolve_highlight` so callers can validate or test their alias configuration
+/// directly.
+pub fn resolve_injection_language(
+    raw: &str,
+    injection_language_regexes: &[(regex::Regex, String)],
+) -> String {
+    let lowercase = raw.to_lowercase();
+    for (regex, name) in injection_language_regexes {
+        if regex.is_match(&lowercase) {
+            return name.clone();
+        }
+    }
+    raw.to_string()
+}
+
+/// Guesses an injection's language by testing its content text against
+/// `content_regexes`, in order, and returning the first match. Used when an injection
+/// doesn't name its language at all (e.g. a shell heredoc, or a markdown fence with no
+/// info string), matching the content-based detection in Helix's `injection-regex`.
+pub fn detect_injection_language_from_content(
+    content_regexes: &[(regex::Regex, String)],
+    text: &str,
+) -> Option<String> {
+    for (regex, name) in content_regexes {
+        if regex.is_match(text) {
+            return Some(name.clone());
+        }
+    }
+    None
 }
 
 #[derive(Debug)]
@@ -101,6 +165,11 @@ struct Layer<'a, S: NodeSource<'a>> {
     _tree: Tree,
     cursor: TreePropertyCursor<'a, Properties, S>,
     ranges: Vec<Range>,
+    language: Language,
+    language_string: String,
+    sheet: &'a PropertySheet<Properties>,
+    parent: Option<LayerId>,
+    children: Vec<LayerId>,
     at_node_end: bool,
     depth: usize,
     opaque: bool,
@@ -108,19 +177,135 @@ struct Layer<'a, S: NodeSource<'a>> {
     local_highlight: Option<Highlight>,
 }
 
+/// A snapshot of one parsed layer, detached from its source and cursor, that can be
+/// handed back to `Highlighter::new_with_edits` after the caller applies a batch of
+/// edits to the document. Kept in a `LayerPool` between highlighting passes.
+pub struct LayerState<'a> {
+    tree: Tree,
+    ranges: Vec<Range>,
+    depth: usize,
+    opaque: bool,
+    language: Language,
+    language_string: String,
+    sheet: &'a PropertySheet<Properties>,
+    parent: Option<LayerId>,
+    children: Vec<LayerId>,
+}
+
+/// The parsed layers left over from a highlighting pass, keyed by `LayerId` so a
+/// subsequent pass (after the caller edits the source) can look one up by its language
+/// and ranges and reuse it instead of reparsing. Obtained via `Highlighter::into_pool`
+/// and passed to `Highlighter::new_with_edits`.
+pub struct LayerPool<'a> {
+    layers: HopSlotMap<LayerId, LayerState<'a>>,
+    index: HashMap<(String, Vec<(usize, usize)>), LayerId>,
+}
+
+impl<'a> LayerPool<'a> {
+    pub fn new() -> Self {
+        Self {
+            layers: HopSlotMap::with_key(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+fn shift_byte(offset: usize, edit: &InputEdit) -> usize {
+    if offset == usize::MAX {
+        offset
+    } else if offset >= edit.old_end_byte {
+        (offset as isize + (edit.new_end_byte as isize - edit.old_end_byte as isize)) as usize
+    } else if offset >= edit.start_byte {
+        edit.new_end_byte
+    } else {
+        offset
+    }
+}
+
+fn shift_point(point: Point, edit: &InputEdit) -> Point {
+    if point.row == usize::MAX {
+        point
+    } else if point >= edit.old_end_position {
+        if point.row == edit.old_end_position.row {
+            Point::new(
+                edit.new_end_position.row + (point.row - edit.old_end_position.row),
+                edit.new_end_position.column + (point.column - edit.old_end_position.column),
+            )
+        } else {
+            Point::new(
+                edit.new_end_position.row + (point.row - edit.old_end_position.row),
+                point.column,
+            )
+        }
+    } else if point >= edit.start_position {
+        edit.new_end_position
+    } else {
+        point
+    }
+}
+
+fn shift_range(range: Range, edit: &InputEdit) -> Range {
+    Range {
+        start_byte: shift_byte(range.start_byte, edit),
+        end_byte: shift_byte(range.end_byte, edit),
+        start_point: shift_point(range.start_point, edit),
+        end_point: shift_point(range.end_point, edit),
+    }
+}
+
+// Shift a layer's included ranges to account for an edit, dropping any range that the
+// edit collapsed to nothing (e.g. the content node it came from was deleted).
+fn shift_ranges(ranges: &[Range], edit: &InputEdit) -> Vec<Range> {
+    ranges
+        .iter()
+        .map(|range| shift_range(*range, edit))
+        .filter(|range| range.start_byte < range.end_byte)
+        .collect()
+}
+
+fn ranges_intersect(ranges: &[Range], edit: &InputEdit) -> bool {
+    ranges
+        .iter()
+        .any(|range| edit.start_byte < range.end_byte && edit.old_end_byte > range.start_byte)
+}
+
 pub struct Highlighter<'a, T, S: NodeSource<'a>>
 where
     T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)>,
 {
     injection_callback: T,
+    injection_language_regexes: &'a [(regex::Regex, String)],
+    content_regexes: &'a [(regex::Regex, String)],
+    recognized_names: &'a [String],
     source: S,
     source_offset: usize,
     parser: Parser,
-    layers: Vec<Layer<'a, S>>,
+    pool: HopSlotMap<LayerId, Layer<'a, S>>,
+    // The layer ids in `pool` that are still being walked, kept sorted by document
+    // position (see `Layer::cmp`) so the lowest-offset layer is always first.
+    order: Vec<LayerId>,
+    // Maps a layer's (language, ranges) to its id, so a node whose injections match a
+    // layer that's already in the pool (created earlier this pass, or carried over from
+    // a previous pass via `LayerPool`) can reuse it instead of reparsing.
+    index: HashMap<(String, Vec<(usize, usize)>), LayerId>,
+    // Every layer id touched during this pass, whether reused or newly created. Layers
+    // not in this set by the end of the pass are unreachable and get dropped by
+    // `into_pool`.
+    visited: HashSet<LayerId>,
+    // Ranges collected so far for "combined" injections (see `Injection::combined`),
+    // keyed by the parent layer and the resolved injection language, so every matching
+    // content node throughout the parent layer's subtree contributes to one merged
+    // layer instead of spawning its own. Materialized into a single `add_layer` call
+    // once the parent layer's subtree has been fully walked (see `remove_first_layer`).
+    pending_combined: HashMap<(LayerId, String), (Vec<Range>, bool)>,
     max_opaque_layer_depth: usize,
     utf8_error_len: Option<usize>,
     operation_count: usize,
     cancellation_flag: Option<&'a AtomicUsize>,
+    // A wall-clock deadline, checked at the same interval as `cancellation_flag`.
+    // Computed once from the caller's `timeout` `Duration` when the `Highlighter` is
+    // constructed, so each check is just a cheap `Instant::now()` comparison.
+    deadline: Option<Instant>,
 }
 
 #[derive(Clone, Debug)]
@@ -173,11 +358,18 @@ enum InjectionIncludesChildrenJSON {
     Single(bool),
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InjectionCombinedJSON {
+    List(Vec<bool>),
+    Single(bool),
+}
+
 #[derive(Debug, Deserialize)]
 struct PropertiesJSON {
-    highlight: Option<Highlight>,
+    highlight: Option<String>,
     #[serde(rename = "highlight-nonlocal")]
-    highlight_nonlocal: Option<Highlight>,
+    highlight_nonlocal: Option<String>,
 
     #[serde(rename = "injection-language")]
     injection_language: Option<InjectionLanguageJSON>,
@@ -185,6 +377,8 @@ struct PropertiesJSON {
     injection_content: Option<InjectionContentJSON>,
     #[serde(default, rename = "injection-includes-children")]
     injection_includes_children: Option<InjectionIncludesChildrenJSON>,
+    #[serde(default, rename = "injection-combined")]
+    injection_combined: Option<InjectionCombinedJSON>,
 
     #[serde(default, rename = "local-scope")]
     local_scope: bool,
@@ -208,6 +402,7 @@ impl fmt::Display for Error {
         match self {
             Error::Cancelled => write!(f, "Cancelled"),
             Error::InvalidLanguage => write!(f, "Invalid language"),
+            Error::Timeout => write!(f, "Timeout"),
             Error::Unknown => write!(f, "Unknown error"),
         }
     }
@@ -249,16 +444,6 @@ pub fn load_property_sheet(
     Ok(sheet)
 }
 
-impl Highlight {
-    pub fn from_usize(i: usize) -> Option<Self> {
-        if i <= (Highlight::Unknown as usize) {
-            Some(unsafe { transmute(i as u16) })
-        } else {
-            None
-        }
-    }
-}
-
 impl Properties {
     fn new(json: PropertiesJSON, language: Language) -> Result<Self, String> {
         let injections = match (json.injection_language, json.injection_content) {
@@ -320,22 +505,39 @@ impl Properties {
                     }],
                 };
 
+                // An empty `List` (a valid `"injection-includes-children": []` or
+                // `"injection-combined": []` in the property sheet JSON) falls back to
+                // the same single-`false` default as an absent key, rather than being
+                // left empty: the `resize(_, includes_children[0])`/`resize(_,
+                // combined[0])` calls below index element `0` unconditionally, which
+                // would otherwise panic on a property sheet that spells out an empty list.
                 let mut includes_children = match json.injection_includes_children {
-                    Some(InjectionIncludesChildrenJSON::List(v)) => v,
+                    Some(InjectionIncludesChildrenJSON::List(v)) if !v.is_empty() => v,
+                    Some(InjectionIncludesChildrenJSON::List(_)) => vec![false],
                     Some(InjectionIncludesChildrenJSON::Single(v)) => vec![v],
                     None => vec![false],
                 };
 
+                let mut combined = match json.injection_combined {
+                    Some(InjectionCombinedJSON::List(v)) if !v.is_empty() => v,
+                    Some(InjectionCombinedJSON::List(_)) => vec![false],
+                    Some(InjectionCombinedJSON::Single(v)) => vec![v],
+                    None => vec![false],
+                };
+
                 if languages.len() == contents.len() {
                     includes_children.resize(languages.len(), includes_children[0]);
+                    combined.resize(languages.len(), combined[0]);
                     Ok(languages
                         .into_iter()
                         .zip(contents.into_iter())
                         .zip(includes_children.into_iter())
-                        .map(|((language, content), includes_children)| Injection {
+                        .zip(combined.into_iter())
+                        .map(|(((language, content), includes_children), combined)| Injection {
                             language,
                             content,
                             includes_children,
+                            combined,
                         })
                         .collect())
                 } else {
@@ -458,7 +660,11 @@ where
         language: Language,
         property_sheet: &'a PropertySheet<Properties>,
         injection_callback: F,
+        injection_language_regexes: &'a [(regex::Regex, String)],
+        content_regexes: &'a [(regex::Regex, String)],
+        recognized_names: &'a [String],
         cancellation_flag: Option<&'a AtomicUsize>,
+        timeout: Option<Duration>,
     ) -> Result<Self, Error> {
         let mut parser = Parser::new();
         unsafe { parser.set_cancellation_flag(cancellation_flag.clone()) };
@@ -466,31 +672,208 @@ where
             .set_language(language)
             .map_err(|_| Error::InvalidLanguage)?;
         let tree = parser.parse_source(&source, None).ok_or_else(|| Error::Cancelled)?;
+        let root = Layer::new(
+            source.clone(),
+            tree,
+            property_sheet,
+            language,
+            String::new(),
+            vec![Range {
+                start_byte: 0,
+                end_byte: usize::MAX,
+                start_point: Point::new(0, 0),
+                end_point: Point::new(usize::MAX, usize::MAX),
+            }],
+            0,
+            true,
+            None,
+        );
+        let mut pool = HopSlotMap::with_key();
+        let mut visited = HashSet::new();
+        let root_id = pool.insert(root);
+        visited.insert(root_id);
         Ok(Self {
             parser,
-            source: source.clone(),
+            source,
             cancellation_flag,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
             injection_callback,
+            injection_language_regexes,
+            content_regexes,
+            recognized_names,
             source_offset: 0,
             operation_count: 0,
             utf8_error_len: None,
             max_opaque_layer_depth: 0,
-            layers: vec![Layer::new(
-                source,
+            pool,
+            order: vec![root_id],
+            index: HashMap::new(),
+            visited,
+            pending_combined: HashMap::new(),
+        })
+    }
+
+    /// Re-highlights `source` after `edits` were applied to it, reusing as much of the
+    /// previous parse as possible instead of reparsing from scratch. `previous` is the
+    /// layer pool left over from a `Highlighter` that ran over the pre-edit source,
+    /// obtained via `Highlighter::into_pool`. Layers whose ranges don't intersect any
+    /// edit keep their old tree unchanged; layers that do intersect an edit are
+    /// reparsed with `parser.parse_source(&source, Some(&old_tree))` so tree-sitter can
+    /// reuse unaffected subtrees. A layer whose ranges are edited away entirely is
+    /// dropped.
+    pub fn new_with_edits(
+        previous: LayerPool<'a>,
+        source: S,
+        edits: &[InputEdit],
+        injection_callback: F,
+        injection_language_regexes: &'a [(regex::Regex, String)],
+        content_regexes: &'a [(regex::Regex, String)],
+        recognized_names: &'a [String],
+        cancellation_flag: Option<&'a AtomicUsize>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let mut parser = Parser::new();
+        unsafe { parser.set_cancellation_flag(cancellation_flag.clone()) };
+
+        let mut pool = HopSlotMap::with_key();
+        let mut index = HashMap::new();
+        let mut visited = HashSet::new();
+        let mut max_opaque_layer_depth = 0;
+        // Old and new ids differ (the slotmap generation changes), so remember the
+        // mapping in order to fix up parent links below.
+        let mut id_map = HashMap::new();
+
+        for (old_id, mut state) in previous.layers {
+            let mut touched = false;
+            for edit in edits {
+                touched |= ranges_intersect(&state.ranges, edit);
+                state.ranges = shift_ranges(&state.ranges, edit);
+                state.tree.edit(edit);
+            }
+            if state.ranges.is_empty() {
+                continue;
+            }
+
+            let tree = if touched {
+                parser
+                    .set_language(state.language)
+                    .map_err(|_| Error::InvalidLanguage)?;
+                parser.set_included_ranges(&state.ranges);
+                parser
+                    .parse_source(&source, Some(&state.tree))
+                    .ok_or_else(|| Error::Cancelled)?
+            } else {
+                state.tree
+            };
+
+            let mut layer = Layer::new(
+                source.clone(),
                 tree,
-                property_sheet,
-                vec![Range {
-                    start_byte: 0,
-                    end_byte: usize::MAX,
-                    start_point: Point::new(0, 0),
-                    end_point: Point::new(usize::MAX, usize::MAX),
-                }],
-                0,
-                true,
-            )],
+                state.sheet,
+                state.language,
+                state.language_string.clone(),
+                state.ranges.clone(),
+                state.depth,
+                state.opaque,
+                state.parent,
+            );
+            layer.children = state.children;
+            if layer.opaque && layer.depth > max_opaque_layer_depth {
+                max_opaque_layer_depth = layer.depth;
+            }
+            let key = layer_key(&state.language_string, &state.ranges);
+            let is_root = layer.parent.is_none();
+            let new_id = pool.insert(layer);
+            id_map.insert(old_id, new_id);
+            index.insert(key, new_id);
+            // The root layer is never rediscovered through `add_layer` (nothing injects
+            // into it), so it has to be marked visited here, same as `Highlighter::new`.
+            // Every other layer only earns `visited` by being rediscovered during the
+            // walk below; otherwise a layer whose content node was restructured (rather
+            // than cleanly edited away) would never be reachable again, yet would still
+            // survive `into_pool`'s garbage collection forever.
+            if is_root {
+                visited.insert(new_id);
+            }
+        }
+
+        // Parent/child ids were captured before the remap above; translate them now
+        // that every surviving layer has its new id, dropping references to layers an
+        // edit deleted entirely.
+        for (_, layer) in pool.iter_mut() {
+            if let Some(parent) = layer.parent {
+                layer.parent = id_map.get(&parent).copied();
+            }
+            layer.children.retain_mut(|child| {
+                if let Some(&new_id) = id_map.get(child) {
+                    *child = new_id;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
+        let mut order: Vec<LayerId> = pool.keys().collect();
+        order.sort_by(|a, b| pool[*a].cmp(&pool[*b]));
+
+        if order.is_empty() {
+            return Err(Error::Unknown);
+        }
+
+        Ok(Self {
+            parser,
+            source,
+            cancellation_flag,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            injection_callback,
+            injection_language_regexes,
+            content_regexes,
+            recognized_names,
+            source_offset: 0,
+            operation_count: 0,
+            utf8_error_len: None,
+            max_opaque_layer_depth,
+            pool,
+            order,
+            index,
+            visited,
+            pending_combined: HashMap::new(),
         })
     }
 
+    /// Detaches this highlighter's layers into a `LayerPool` for reuse by a future call
+    /// to `Highlighter::new_with_edits`. Layers that were never visited during this pass
+    /// (e.g. an injection layer created for a node that's no longer reachable once the
+    /// cursor passed it by) are dropped rather than carried forward.
+    pub fn into_pool(mut self) -> LayerPool<'a> {
+        let mut layers = HopSlotMap::with_key();
+        let mut index = HashMap::new();
+        let mut id_map = HashMap::new();
+        for id in self.visited.drain() {
+            if let Some(layer) = self.pool.remove(id) {
+                let key = layer_key(&layer.language_string, &layer.ranges);
+                let new_id = layers.insert(layer.into_state());
+                id_map.insert(id, new_id);
+                index.insert(key, new_id);
+            }
+        }
+        for (_, state) in layers.iter_mut() {
+            if let Some(parent) = state.parent {
+                state.parent = id_map.get(&parent).copied();
+            }
+            state.children.retain_mut(|child| {
+                if let Some(&new_id) = id_map.get(child) {
+                    *child = new_id;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        LayerPool { layers, index }
+    }
+
     fn emit_source(&mut self, next_offset: usize) -> Option<Result<HighlightEvent<'a>, Error>> {
         let input = self.source.bytes(self.source_offset, next_offset);
         match cow::decode_utf8(input) {
@@ -549,7 +932,24 @@ where
                         }
                     }
                 }
-                TreeStep::Next { .. } => unimplemented!(),
+                TreeStep::Next { kinds } => {
+                    // Walk forward through the following siblings, skipping any whose
+                    // kind isn't in `kinds`, and push the first one that matches (or the
+                    // very next sibling, if there's no kind filter).
+                    let mut sibling = node.next_sibling();
+                    while let Some(s) = sibling {
+                        if let Some(kinds) = kinds {
+                            if kinds.contains(&s.kind_id()) {
+                                nodes.push(s);
+                                break;
+                            }
+                            sibling = s.next_sibling();
+                        } else {
+                            nodes.push(s);
+                            break;
+                        }
+                    }
+                }
             }
         }
         nodes.drain(0..len);
@@ -679,13 +1079,40 @@ where
         result
     }
 
+    // Insert a layer into `order`, keeping it sorted by document position.
+    fn insert_into_order(&mut self, id: LayerId) {
+        match self.order.binary_search_by(|&o| self.pool[o].cmp(&self.pool[id])) {
+            Ok(i) | Err(i) => self.order.insert(i, id),
+        }
+    }
+
     fn add_layer(
         &mut self,
         language_string: &str,
         ranges: Vec<Range>,
         depth: usize,
         includes_children: bool,
+        parent: Option<LayerId>,
     ) -> Option<Error> {
+        let key = layer_key(language_string, &ranges);
+
+        // If a layer already in the pool covers the same language and ranges, reuse it
+        // (either it survived from a previous pass via `LayerPool`, or an earlier node
+        // in this same pass matched the same injection). The layer is put (back) into
+        // `order` only if it isn't already there; otherwise it's just marked visited.
+        if let Some(&id) = self.index.get(&key) {
+            self.visited.insert(id);
+            if !self.order.contains(&id) {
+                self.insert_into_order(id);
+            }
+            if let Some(parent) = parent {
+                if !self.pool[parent].children.contains(&id) {
+                    self.pool[parent].children.push(id);
+                }
+            }
+            return None;
+        }
+
         if let Some((language, property_sheet)) = (self.injection_callback)(language_string) {
             if self.parser.set_language(language).is_err() {
                 return Some(Error::InvalidLanguage);
@@ -696,16 +1123,23 @@ where
                     self.source.clone(),
                     tree,
                     property_sheet,
+                    language,
+                    language_string.to_string(),
                     ranges,
                     depth,
                     includes_children,
+                    parent,
                 );
                 if includes_children && depth > self.max_opaque_layer_depth {
                     self.max_opaque_layer_depth = depth;
                 }
-                match self.layers.binary_search_by(|l| l.cmp(&layer)) {
-                    Ok(i) | Err(i) => self.layers.insert(i, layer),
-                };
+                let id = self.pool.insert(layer);
+                self.visited.insert(id);
+                self.index.insert(key, id);
+                if let Some(parent) = parent {
+                    self.pool[parent].children.push(id);
+                }
+                self.insert_into_order(id);
             } else {
                 return Some(Error::Cancelled);
             }
@@ -713,16 +1147,69 @@ where
         None
     }
 
-    fn remove_first_layer(&mut self) {
-        let layer = self.layers.remove(0);
+    fn remove_first_layer(&mut self) -> Option<Error> {
+        let id = self.order.remove(0);
+        let layer = &self.pool[id];
         if layer.opaque && layer.depth == self.max_opaque_layer_depth {
             self.max_opaque_layer_depth = self
-                .layers
+                .order
                 .iter()
-                .filter_map(|l| if l.opaque { Some(l.depth) } else { None })
+                .filter_map(|&o| {
+                    let l = &self.pool[o];
+                    if l.opaque {
+                        Some(l.depth)
+                    } else {
+                        None
+                    }
+                })
                 .max()
                 .unwrap_or(0);
         }
+
+        // This layer's subtree has now been fully walked, so any "combined" injections
+        // it queued can be materialized: one layer per language, covering every matching
+        // content node's ranges, merged into non-overlapping ranges in ascending order
+        // since `set_included_ranges` requires that.
+        let depth = self.pool[id].depth + 1;
+        let keys: Vec<(LayerId, String)> = self
+            .pending_combined
+            .keys()
+            .filter(|(parent, _)| *parent == id)
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some((ranges, includes_children)) = self.pending_combined.remove(&key) {
+                let ranges = Self::merge_ranges(ranges);
+                if let Some(error) =
+                    self.add_layer(&key.1, ranges, depth, includes_children, Some(id))
+                {
+                    return Some(error);
+                }
+            }
+        }
+        None
+    }
+
+    // Sort a combined injection's accumulated ranges and merge any that overlap or abut,
+    // so two content nodes matched for the same language (e.g. adjacent `${...}`
+    // interpolations in a template) don't produce overlapping ranges, which
+    // `set_included_ranges` rejects.
+    fn merge_ranges(mut ranges: Vec<Range>) -> Vec<Range> {
+        ranges.sort_by_key(|r| r.start_byte);
+        let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            if let Some(last) = merged.last_mut() {
+                if range.start_byte <= last.end_byte {
+                    if range.end_byte > last.end_byte {
+                        last.end_byte = range.end_byte;
+                        last.end_point = range.end_point;
+                    }
+                    continue;
+                }
+            }
+            merged.push(range);
+        }
+        merged
     }
 }
 
@@ -733,14 +1220,19 @@ where
     type Item = Result<HighlightEvent<'a>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(cancellation_flag) = self.cancellation_flag {
-            self.operation_count += 1;
-            if self.operation_count >= CANCELLATION_CHECK_INTERVAL {
-                self.operation_count = 0;
+        self.operation_count += 1;
+        if self.operation_count >= CANCELLATION_CHECK_INTERVAL {
+            self.operation_count = 0;
+            if let Some(cancellation_flag) = self.cancellation_flag {
                 if cancellation_flag.load(Ordering::Relaxed) != 0 {
                     return Some(Err(Error::Cancelled));
                 }
             }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Some(Err(Error::Timeout));
+                }
+            }
         }
 
         if let Some(utf8_error_len) = self.utf8_error_len.take() {
@@ -748,9 +1240,10 @@ where
             return Some(Ok(HighlightEvent::Source(Cow::Borrowed("\u{FFFD}"))));
         }
 
-        while !self.layers.is_empty() {
+        while !self.order.is_empty() {
             let mut scope_event = None;
-            let first_layer = &self.layers[0];
+            let first_id = self.order[0];
+            let first_layer = &self.pool[first_id];
 
             // If the current layer is not covered up by a nested layer, then
             // process any scope boundaries and language injections for the layer's
@@ -760,40 +1253,64 @@ where
                 let local_highlight = first_layer.local_highlight;
                 let properties = &first_layer.cursor.node_properties();
 
-                // Add any injections for the current node.
+                // Add any injections for the current node. A node may match more than
+                // one injection rule, so queue them up and process them breadth-first
+                // (all of this node's injections before any of the layers they spawn
+                // get their own injections processed), mirroring the document order in
+                // which the cursor will reach them.
                 if !first_layer.at_node_end {
                     let node = first_layer.cursor.node();
-                    let injections = properties
-                        .injections
-                        .iter()
-                        .filter_map(
-                            |Injection {
-                                 language,
-                                 content,
-                                 includes_children,
-                             }| {
-                                if let Some(language) =
-                                    self.injection_language_string(&node, language)
-                                {
-                                    let nodes = self.nodes_for_tree_path(node, content);
-                                    let ranges = Self::intersect_ranges(
-                                        &first_layer.ranges,
-                                        &nodes,
-                                        *includes_children,
-                                    );
-                                    if ranges.len() > 0 {
-                                        return Some((language, ranges, *includes_children));
-                                    }
-                                }
-                                None
-                            },
-                        )
-                        .collect::<Vec<_>>();
+                    let mut pending: VecDeque<(String, Vec<Range>, bool)> = VecDeque::new();
+                    for Injection {
+                        language,
+                        content,
+                        includes_children,
+                        combined,
+                    } in &properties.injections
+                    {
+                        let nodes = self.nodes_for_tree_path(node, content);
+                        let language = self
+                            .injection_language_string(&node, language)
+                            .map(|language| {
+                                resolve_injection_language(&language, self.injection_language_regexes)
+                            })
+                            .or_else(|| {
+                                let node = nodes.first()?;
+                                let bytes = self.source.bytes(node.start_byte(), node.end_byte());
+                                let text = str::from_utf8(bytes.as_ref()).ok()?;
+                                detect_injection_language_from_content(self.content_regexes, text)
+                            });
+                        if let Some(language) = language {
+                            let ranges = Self::intersect_ranges(
+                                &first_layer.ranges,
+                                &nodes,
+                                *includes_children,
+                            );
+                            if ranges.is_empty() {
+                                continue;
+                            }
+                            // A "combined" injection doesn't spawn its own layer per match;
+                            // instead its ranges accumulate here until the parent layer's
+                            // subtree is fully walked, at which point `remove_first_layer`
+                            // materializes one merged layer per language (see
+                            // `pending_combined`).
+                            if *combined {
+                                let entry = self
+                                    .pending_combined
+                                    .entry((first_id, language))
+                                    .or_insert_with(|| (Vec::new(), false));
+                                entry.0.extend(ranges);
+                                entry.1 |= *includes_children;
+                            } else {
+                                pending.push_back((language, ranges, *includes_children));
+                            }
+                        }
+                    }
 
                     let depth = first_layer.depth + 1;
-                    for (language, ranges, includes_children) in injections {
+                    while let Some((language, ranges, includes_children)) = pending.pop_front() {
                         if let Some(error) =
-                            self.add_layer(&language, ranges, depth, includes_children)
+                            self.add_layer(&language, ranges, depth, includes_children, Some(first_id))
                         {
                             return Some(Err(error));
                         }
@@ -801,10 +1318,20 @@ where
                 }
 
                 // Determine if any scopes start or end at the current position.
-                let first_layer = &mut self.layers[0];
+                let first_layer = &mut self.pool[first_id];
                 if let Some(highlight) = local_highlight
-                    .or(properties.highlight_nonlocal)
-                    .or(properties.highlight)
+                    .or_else(|| {
+                        properties
+                            .highlight_nonlocal
+                            .as_deref()
+                            .and_then(|scope| resolve_highlight(self.recognized_names, scope))
+                    })
+                    .or_else(|| {
+                        properties
+                            .highlight
+                            .as_deref()
+                            .and_then(|scope| resolve_highlight(self.recognized_names, scope))
+                    })
                 {
                     let next_offset = cmp::min(self.source.max_len(), first_layer.offset());
 
@@ -826,16 +1353,16 @@ where
             // beyond one of the other layers' cursors for a different syntax tree, so we need
             // to re-sort the layers. If the cursor is already at the end of its syntax tree,
             // remove it.
-            if self.layers[0].advance() {
+            if self.pool[first_id].advance(self.recognized_names) {
                 let mut index = 0;
-                while self.layers.get(index + 1).map_or(false, |next| {
-                    self.layers[index].cmp(next) == cmp::Ordering::Greater
+                while self.order.get(index + 1).map_or(false, |&next| {
+                    self.pool[self.order[index]].cmp(&self.pool[next]) == cmp::Ordering::Greater
                 }) {
-                    self.layers.swap(index, index + 1);
+                    self.order.swap(index, index + 1);
                     index += 1;
                 }
-            } else {
-                self.remove_first_layer();
+            } else if let Some(error) = self.remove_first_layer() {
+                return Some(Err(error));
             }
 
             if scope_event.is_some() {
@@ -856,7 +1383,7 @@ where
     T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)>,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(layer) = self.layers.first() {
+        if let Some(layer) = self.order.first().map(|&id| &self.pool[id]) {
             let node = layer.cursor.node();
             let position = if layer.at_node_end {
                 node.end_position()
@@ -881,9 +1408,12 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
         source: S,
         tree: Tree,
         sheet: &'a PropertySheet<Properties>,
+        language: Language,
+        language_string: String,
         ranges: Vec<Range>,
         depth: usize,
         opaque: bool,
+        parent: Option<LayerId>,
     ) -> Self {
         // The cursor's lifetime parameter indicates that the tree must outlive the cursor.
         // But because the tree is really a pointer to the heap, the cursor can remain
@@ -895,6 +1425,11 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
             _tree: tree,
             cursor,
             ranges,
+            language,
+            language_string,
+            sheet,
+            parent,
+            children: Vec::new(),
             depth,
             opaque,
             at_node_end: false,
@@ -906,6 +1441,22 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
         }
     }
 
+    // Detach this layer from its cursor and source, keeping just enough state to
+    // resume highlighting after an edit (see `Highlighter::new_with_edits`).
+    fn into_state(self) -> LayerState<'a> {
+        LayerState {
+            tree: self._tree,
+            ranges: self.ranges,
+            depth: self.depth,
+            opaque: self.opaque,
+            language: self.language,
+            language_string: self.language_string,
+            sheet: self.sheet,
+            parent: self.parent,
+            children: self.children,
+        }
+    }
+
     fn cmp(&self, other: &Layer<'a, S>) -> cmp::Ordering {
         // Events are ordered primarily by their position in the document. But if
         // one highlight starts at a given position and another highlight ends at that
@@ -924,7 +1475,7 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
         }
     }
 
-    fn advance(&mut self) -> bool {
+    fn advance(&mut self, recognized_names: &[String]) -> bool {
         // Clear the current local highlighting class, which may be re-populated
         // if we enter a node that represents a local definition or local reference.
         self.local_highlight = None;
@@ -934,20 +1485,20 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
         if self.at_node_end {
             self.leave_node();
             if self.cursor.goto_next_sibling() {
-                self.enter_node();
+                self.enter_node(recognized_names);
                 self.at_node_end = false;
             } else if !self.cursor.goto_parent() {
                 return false;
             }
         } else if self.cursor.goto_first_child() {
-            self.enter_node();
+            self.enter_node(recognized_names);
         } else {
             self.at_node_end = true;
         }
         true
     }
 
-    fn enter_node(&mut self) {
+    fn enter_node(&mut self, recognized_names: &[String]) {
         let props = self.cursor.node_properties();
         let bytes = self.cursor.node_bytes();
         let node_text = if props.local_definition || props.local_reference {
@@ -959,10 +1510,14 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
         // If this node represents a local definition, then record its highlighting class
         // and store the highlighting class in the current local scope.
         if props.local_definition {
+            let highlight = props
+                .highlight
+                .as_deref()
+                .and_then(|scope| resolve_highlight(recognized_names, scope));
             if let (Some(text), Some(inner_scope), Some(highlight)) =
-                (node_text, self.scope_stack.last_mut(), props.highlight)
+                (node_text, self.scope_stack.last_mut(), highlight)
             {
-                self.local_highlight = props.highlight;
+                self.local_highlight = Some(highlight);
                 let text_r = text.as_ref();
                 if let Err(i) = inner_scope.local_defs.binary_search_by_key(&text_r, |e| e.0.as_ref()) {
                     inner_scope.local_defs.insert(i, (text, highlight));
@@ -1002,80 +1557,133 @@ impl<'a, S: NodeSource<'a>> Layer<'a, S> {
     }
 }
 
-impl<'de> Deserialize<'de> for Highlight {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        match s.as_str() {
-            "attribute" => Ok(Highlight::Attribute),
-            "comment" => Ok(Highlight::Comment),
-            "constant" => Ok(Highlight::Constant),
-            "constant.builtin" => Ok(Highlight::ConstantBuiltin),
-            "constructor" => Ok(Highlight::Constructor),
-            "constructor.builtin" => Ok(Highlight::ConstructorBuiltin),
-            "embedded" => Ok(Highlight::Embedded),
-            "escape" => Ok(Highlight::Escape),
-            "function" => Ok(Highlight::Function),
-            "function.builtin" => Ok(Highlight::FunctionBuiltin),
-            "keyword" => Ok(Highlight::Keyword),
-            "number" => Ok(Highlight::Number),
-            "operator" => Ok(Highlight::Operator),
-            "property" => Ok(Highlight::Property),
-            "property.builtin" => Ok(Highlight::PropertyBuiltin),
-            "punctuation" => Ok(Highlight::Punctuation),
-            "punctuation.bracket" => Ok(Highlight::PunctuationBracket),
-            "punctuation.delimiter" => Ok(Highlight::PunctuationDelimiter),
-            "punctuation.special" => Ok(Highlight::PunctuationSpecial),
-            "string" => Ok(Highlight::String),
-            "string.special" => Ok(Highlight::StringSpecial),
-            "type" => Ok(Highlight::Type),
-            "type.builtin" => Ok(Highlight::TypeBuiltin),
-            "variable" => Ok(Highlight::Variable),
-            "variable.builtin" => Ok(Highlight::VariableBuiltin),
-            "variable.parameter" => Ok(Highlight::VariableParameter),
-            "tag" => Ok(Highlight::Tag),
-            _ => Ok(Highlight::Unknown),
+/// A reusable highlighting session for a single buffer that's edited and re-highlighted
+/// repeatedly (the typical editor use case). This is a thin wrapper around
+/// `Highlighter::new` / `Highlighter::new_with_edits` and `Highlighter::into_pool`: it
+/// holds onto the previous pass's `LayerPool` so callers don't have to juggle it
+/// themselves between calls to `highlight`.
+pub struct HighlightSession<'a, T>
+where
+    T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)> + Copy,
+{
+    language: Language,
+    property_sheet: &'a PropertySheet<Properties>,
+    injection_callback: T,
+    injection_language_regexes: &'a [(regex::Regex, String)],
+    content_regexes: &'a [(regex::Regex, String)],
+    recognized_names: &'a [String],
+    cancellation_flag: Option<&'a AtomicUsize>,
+    // A wall-clock budget applied to each call to `highlight`, independently of
+    // `cancellation_flag`. See `Highlighter`'s `deadline` field.
+    timeout: Option<Duration>,
+    pool: Option<LayerPool<'a>>,
+}
+
+impl<'a, T> HighlightSession<'a, T>
+where
+    T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)> + Copy,
+{
+    pub fn new(
+        language: Language,
+        property_sheet: &'a PropertySheet<Properties>,
+        injection_callback: T,
+        injection_language_regexes: &'a [(regex::Regex, String)],
+        content_regexes: &'a [(regex::Regex, String)],
+        recognized_names: &'a [String],
+        cancellation_flag: Option<&'a AtomicUsize>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            language,
+            property_sheet,
+            injection_callback,
+            injection_language_regexes,
+            content_regexes,
+            recognized_names,
+            cancellation_flag,
+            timeout,
+            pool: None,
         }
     }
+
+    /// Highlights `source`, the buffer's contents *after* applying `edits`. On the
+    /// first call (or after `reset`), this parses from scratch, same as `Highlighter::new`.
+    /// On later calls, it reuses the layer trees retained from the previous call via
+    /// `Highlighter::new_with_edits`, so only the layers whose ranges intersect `edits`
+    /// are reparsed. Pass an empty `edits` slice to re-highlight unchanged source, reusing
+    /// every retained layer tree as it is.
+    pub fn highlight<'s, S: NodeSource<'a>>(
+        &'s mut self,
+        source: S,
+        edits: &[InputEdit],
+    ) -> Result<SessionHighlightEvents<'s, 'a, T, S>, Error> {
+        let highlighter = match self.pool.take() {
+            Some(pool) => Highlighter::new_with_edits(
+                pool,
+                source,
+                edits,
+                self.injection_callback,
+                self.injection_language_regexes,
+                self.content_regexes,
+                self.recognized_names,
+                self.cancellation_flag,
+                self.timeout,
+            )?,
+            None => Highlighter::new(
+                source,
+                self.language,
+                self.property_sheet,
+                self.injection_callback,
+                self.injection_language_regexes,
+                self.content_regexes,
+                self.recognized_names,
+                self.cancellation_flag,
+                self.timeout,
+            )?,
+        };
+        Ok(SessionHighlightEvents {
+            session: self,
+            highlighter: Some(highlighter),
+        })
+    }
+
+    /// Discards the retained layer trees, so the next call to `highlight` parses from
+    /// scratch. Useful when the language or property sheet changes out from under the
+    /// session.
+    pub fn reset(&mut self) {
+        self.pool = None;
+    }
 }
 
-impl Serialize for Highlight {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match self {
-            Highlight::Attribute => serializer.serialize_str("attribute"),
-            Highlight::Comment => serializer.serialize_str("comment"),
-            Highlight::Constant => serializer.serialize_str("constant"),
-            Highlight::ConstantBuiltin => serializer.serialize_str("constant.builtin"),
-            Highlight::Constructor => serializer.serialize_str("constructor"),
-            Highlight::ConstructorBuiltin => serializer.serialize_str("constructor.builtin"),
-            Highlight::Embedded => serializer.serialize_str("embedded"),
-            Highlight::Escape => serializer.serialize_str("escape"),
-            Highlight::Function => serializer.serialize_str("function"),
-            Highlight::FunctionBuiltin => serializer.serialize_str("function.builtin"),
-            Highlight::Keyword => serializer.serialize_str("keyword"),
-            Highlight::Number => serializer.serialize_str("number"),
-            Highlight::Operator => serializer.serialize_str("operator"),
-            Highlight::Property => serializer.serialize_str("property"),
-            Highlight::PropertyBuiltin => serializer.serialize_str("property.builtin"),
-            Highlight::Punctuation => serializer.serialize_str("punctuation"),
-            Highlight::PunctuationBracket => serializer.serialize_str("punctuation.bracket"),
-            Highlight::PunctuationDelimiter => serializer.serialize_str("punctuation.delimiter"),
-            Highlight::PunctuationSpecial => serializer.serialize_str("punctuation.special"),
-            Highlight::String => serializer.serialize_str("string"),
-            Highlight::StringSpecial => serializer.serialize_str("string.special"),
-            Highlight::Type => serializer.serialize_str("type"),
-            Highlight::TypeBuiltin => serializer.serialize_str("type.builtin"),
-            Highlight::Variable => serializer.serialize_str("variable"),
-            Highlight::VariableBuiltin => serializer.serialize_str("variable.builtin"),
-            Highlight::VariableParameter => serializer.serialize_str("variable.parameter"),
-            Highlight::Tag => serializer.serialize_str("tag"),
-            Highlight::Unknown => serializer.serialize_str(""),
+/// The iterator returned by `HighlightSession::highlight`. Behaves exactly like the
+/// iterator returned by `highlight`/`Highlighter::new`, except that once it's driven to
+/// completion it hands its layer trees back to the `HighlightSession` for reuse by the
+/// next call. An iterator that's dropped before being fully consumed does not retain
+/// its layers; the next `highlight` call will reparse from scratch.
+pub struct SessionHighlightEvents<'s, 'a, T, S>
+where
+    T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)> + Copy,
+    S: NodeSource<'a>,
+{
+    session: &'s mut HighlightSession<'a, T>,
+    highlighter: Option<Highlighter<'a, T, S>>,
+}
+
+impl<'s, 'a, T, S> Iterator for SessionHighlightEvents<'s, 'a, T, S>
+where
+    T: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)> + Copy,
+    S: NodeSource<'a>,
+{
+    type Item = Result<HighlightEvent<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.highlighter.as_mut()?.next();
+        if item.is_none() {
+            if let Some(highlighter) = self.highlighter.take() {
+                self.session.pool = Some(highlighter.into_pool());
+            }
         }
+        item
     }
 }
 
@@ -1087,6 +1695,10 @@ pub fn highlight<'a, F>(
     property_sheet: &'a PropertySheet<Properties>,
     cancellation_flag: Option<&'a AtomicUsize>,
     injection_callback: F,
+    injection_language_regexes: &'a [(regex::Regex, String)],
+    content_regexes: &'a [(regex::Regex, String)],
+    recognized_names: &'a [String],
+    timeout: Option<Duration>,
 ) -> Result<impl Iterator<Item = Result<HighlightEvent<'a>, Error>> + 'a, Error>
 where
     F: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)> + 'a,
@@ -1096,7 +1708,11 @@ where
         language,
         property_sheet,
         injection_callback,
+        injection_language_regexes,
+        content_regexes,
+        recognized_names,
         cancellation_flag,
+        timeout,
     )
 }
 
@@ -1106,6 +1722,10 @@ pub fn highlight_html<'a, F1, F2>(
     property_sheet: &'a PropertySheet<Properties>,
     cancellation_flag: Option<&'a AtomicUsize>,
     injection_callback: F1,
+    injection_language_regexes: &'a [(regex::Regex, String)],
+    content_regexes: &'a [(regex::Regex, String)],
+    recognized_names: &'a [String],
+    timeout: Option<Duration>,
     attribute_callback: F2,
 ) -> Result<Vec<String>, Error>
 where
@@ -1117,82 +1737,495 @@ where
         language,
         property_sheet,
         injection_callback,
+        injection_language_regexes,
+        content_regexes,
+        recognized_names,
         cancellation_flag,
+        timeout,
     )?;
-    let mut renderer = HtmlRenderer::new(attribute_callback);
-    let mut scopes = Vec::new();
-    for event in highlighter {
-        let event = event?;
-        match event {
-            HighlightEvent::HighlightStart(s) => {
-                scopes.push(s);
-                renderer.start_scope(s);
+    let renderer = html::HtmlRenderer::new(attribute_callback);
+    let lines = renderer.render_lines(highlighter)?;
+    Ok(lines.into_iter().map(|(html, _)| html).collect())
+}
+
+/// Flattens a `HighlightEvent` stream into non-overlapping byte ranges, each tagged with
+/// the innermost `Highlight` active over that range (the topmost entry on the open-scope
+/// stack wins when scopes nest). This is the shape editors want for painting text
+/// decorations directly, without re-implementing the start/end stack bookkeeping that the
+/// raw `HighlightStart`/`HighlightEnd` events require. Source text with no highlight
+/// active (e.g. whitespace between tokens) is simply omitted from the result.
+pub fn highlight_ranges<'a, I>(events: I) -> Result<Vec<(std::ops::Range<usize>, Highlight)>, Error>
+where
+    I: IntoIterator<Item = Result<HighlightEvent<'a>, Error>>,
+{
+    let mut open_highlights: Vec<Highlight> = Vec::new();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for event in events {
+        match event? {
+            HighlightEvent::Source(src) => {
+                let len = src.len();
+                if let Some(&highlight) = open_highlights.last() {
+                    ranges.push((offset..offset + len, highlight));
+                }
+                offset += len;
             }
+            HighlightEvent::HighlightStart(h) => open_highlights.push(h),
             HighlightEvent::HighlightEnd => {
-                scopes.pop();
-                renderer.end_scope();
+                open_highlights.pop();
             }
-            HighlightEvent::Source(src) => {
-                renderer.add_text(src.as_ref(), &scopes);
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A host/injection pair (HTML with injected `<script>` content) is the simplest
+    // real grammar combination that exercises `new_with_edits`' layer reuse, since the
+    // host node that triggers the injection (`script_element`) can change kind under an
+    // edit that never overlaps the injected content's own byte range.
+    const HTML_SCRIPT_INJECTION_JSON: &str = r#"[
+        {
+            "selector": "script_element",
+            "properties": {
+                "injection-language": "javascript",
+                "injection-content": { "name": "child", "args": [{ "name": "this" }, 1] }
             }
+        }
+    ]"#;
+
+    fn empty_sheet(language: Language) -> PropertySheet<Properties> {
+        load_property_sheet(language, "[]").unwrap()
+    }
+
+    fn run_to_end<'a, F: Fn(&str) -> Option<(Language, &'a PropertySheet<Properties>)>>(
+        mut highlighter: Highlighter<'a, F, &'a [u8]>,
+    ) -> LayerPool<'a> {
+        for event in &mut highlighter {
+            event.unwrap();
+        }
+        highlighter.into_pool()
+    }
+
+    // The `next` tree-path step used to be `unimplemented!()`. Drive it through the
+    // public injection-language API (rather than calling the private tree-walking method
+    // directly) so the test exercises the same path a property sheet author would hit.
+    #[test]
+    fn next_tree_step_walks_forward_through_siblings() {
+        use std::cell::RefCell;
+
+        const WITHOUT_KIND_FILTER: &str = r#"[
+            {
+                "selector": "number",
+                "properties": {
+                    "injection-language": { "name": "next", "args": [{ "name": "this" }] },
+                    "injection-content": { "name": "this" }
+                }
+            }
+        ]"#;
+        const WITH_KIND_FILTER: &str = r#"[
+            {
+                "selector": "number",
+                "properties": {
+                    "injection-language": {
+                        "name": "next",
+                        "args": [{ "name": "this" }, "number"]
+                    },
+                    "injection-content": { "name": "this" }
+                }
+            }
+        ]"#;
+
+        let json = tree_sitter_json::language();
+        let source: &[u8] = b"[1,\"a\",2]";
+
+        // No `kinds` filter: `next` should land on the very next sibling, whatever kind
+        // it is (here, the `,` after the first number and the closing `]` after the last).
+        let sheet = load_property_sheet(json, WITHOUT_KIND_FILTER).unwrap();
+        let calls = RefCell::new(Vec::new());
+        let callback = |name: &str| {
+            calls.borrow_mut().push(name.to_string());
+            None
+        };
+        let mut highlighter =
+            Highlighter::new(source, json, &sheet, callback, &[], &[], &[], None, None).unwrap();
+        for event in &mut highlighter {
+            event.unwrap();
+        }
+        assert_eq!(calls.into_inner(), vec![",".to_string(), "]".to_string()]);
+
+        // With a `kinds` filter: `next` should skip over non-matching siblings (the
+        // punctuation and the string literal) to reach the next `number`, and must not
+        // panic when a trailing node (the second `number`, with no further `number`
+        // sibling before the closing bracket) has nothing left to match.
+        let sheet = load_property_sheet(json, WITH_KIND_FILTER).unwrap();
+        let calls = RefCell::new(Vec::new());
+        let callback = |name: &str| {
+            calls.borrow_mut().push(name.to_string());
+            None
         };
+        let mut highlighter =
+            Highlighter::new(source, json, &sheet, callback, &[], &[], &[], None, None).unwrap();
+        for event in &mut highlighter {
+            event.unwrap();
+        }
+        assert_eq!(calls.into_inner(), vec!["2".to_string()]);
     }
-    if !renderer.current_line.is_empty() {
-        renderer.finish_line();
+
+    // Covers the acceptance criterion from the original request: an injection layer
+    // whose content node disappears must be dropped, even when that happens because
+    // the content's *parent* node changed kind (here, `<script>` renamed to `<style>`)
+    // rather than because the edit directly collapsed the layer's own ranges to empty.
+    #[test]
+    fn renaming_the_injection_site_drops_the_stale_layer() {
+        let html = tree_sitter_html::language();
+        let js = tree_sitter_javascript::language();
+        let html_sheet = load_property_sheet(html, HTML_SCRIPT_INJECTION_JSON).unwrap();
+        let js_sheet = empty_sheet(js);
+        let callback = |name: &str| {
+            if name == "javascript" {
+                Some((js, &js_sheet))
+            } else {
+                None
+            }
+        };
+
+        let before: &[u8] = b"<script>1</script>";
+        let pool = run_to_end(
+            Highlighter::new(before, html, &html_sheet, callback, &[], &[], &[], None, None)
+                .unwrap(),
+        );
+        assert_eq!(
+            pool.layers.len(),
+            2,
+            "expected the html root layer plus one javascript injection layer"
+        );
+
+        // Rename `script` to `style` in place; this edit's byte range sits entirely
+        // before the injected content, so it never intersects the javascript layer's
+        // own ranges.
+        let after: &[u8] = b"<style>1</style>";
+        let edit = InputEdit {
+            start_byte: 1,
+            old_end_byte: 7,
+            new_end_byte: 6,
+            start_position: Point::new(0, 1),
+            old_end_position: Point::new(0, 7),
+            new_end_position: Point::new(0, 6),
+        };
+
+        let pool = run_to_end(
+            Highlighter::new_with_edits(pool, after, &[edit], callback, &[], &[], &[], None, None)
+                .unwrap(),
+        );
+        assert_eq!(
+            pool.layers.len(),
+            1,
+            "the script_element this layer was injected into no longer exists, so the \
+             javascript layer must not survive into the next pool"
+        );
     }
-    Ok(renderer.result)
-}
 
-struct HtmlRenderer<'a, F: Fn(Highlight) -> &'a str> {
-    result: Vec<String>,
-    current_line: String,
-    attribute_callback: F,
-}
+    // `HighlightSession` is the primary wrapper real editors drive repeatedly, routing
+    // every call after the first through `Highlighter::new_with_edits`. Exercise a few
+    // passes so a stale injection layer (see above) doesn't linger or get re-counted
+    // across the session's retained pool.
+    #[test]
+    fn session_drops_stale_injection_layers_across_repeated_edits() {
+        let html = tree_sitter_html::language();
+        let js = tree_sitter_javascript::language();
+        let html_sheet = load_property_sheet(html, HTML_SCRIPT_INJECTION_JSON).unwrap();
+        let js_sheet = empty_sheet(js);
+        let callback = |name: &str| {
+            if name == "javascript" {
+                Some((js, &js_sheet))
+            } else {
+                None
+            }
+        };
 
-impl<'a, F> HtmlRenderer<'a, F>
-where
-    F: Fn(Highlight) -> &'a str,
-{
-    fn new(attribute_callback: F) -> Self {
-        HtmlRenderer {
-            result: Vec::new(),
-            current_line: String::new(),
-            attribute_callback,
+        let mut session =
+            HighlightSession::new(html, &html_sheet, callback, &[], &[], &[], None, None);
+
+        let before: &[u8] = b"<script>1</script>";
+        for event in session.highlight(before, &[]).unwrap() {
+            event.unwrap();
         }
+        assert_eq!(session.pool.as_ref().unwrap().layers.len(), 2);
+
+        let after: &[u8] = b"<style>1</style>";
+        let edit = InputEdit {
+            start_byte: 1,
+            old_end_byte: 7,
+            new_end_byte: 6,
+            start_position: Point::new(0, 1),
+            old_end_position: Point::new(0, 7),
+            new_end_position: Point::new(0, 6),
+        };
+        for event in session.highlight(after, &[edit]).unwrap() {
+            event.unwrap();
+        }
+        assert_eq!(
+            session.pool.as_ref().unwrap().layers.len(),
+            1,
+            "the stale javascript layer must be dropped after the edit pass that \
+             renamed its injection site, not carried forward by the session"
+        );
+
+        // A further no-op pass (no edits) must not resurrect the dropped layer.
+        for event in session.highlight(after, &[]).unwrap() {
+            event.unwrap();
+        }
+        assert_eq!(session.pool.as_ref().unwrap().layers.len(), 1);
     }
 
-    fn start_scope(&mut self, s: Highlight) {
-        write!(
-            &mut self.current_line,
-            "<span {}>",
-            (self.attribute_callback)(s),
-        )
-        .unwrap();
+    // `merge_ranges` doesn't depend on `F`/`S` at all, but it's only reachable through a
+    // monomorphized `Highlighter`; a fn-pointer/`&'static [u8]` instantiation is as good
+    // as any other for calling it directly.
+    fn merge_ranges(ranges: Vec<Range>) -> Vec<Range> {
+        type Callback = fn(&str) -> Option<(Language, &'static PropertySheet<Properties>)>;
+        Highlighter::<Callback, &'static [u8]>::merge_ranges(ranges)
     }
 
-    fn end_scope(&mut self) {
-        write!(&mut self.current_line, "</span>").unwrap();
+    fn range(start: usize, end: usize) -> Range {
+        Range {
+            start_byte: start,
+            end_byte: end,
+            start_point: Point::new(0, start),
+            end_point: Point::new(0, end),
+        }
     }
 
-    fn finish_line(&mut self) {
-        self.current_line.push('\n');
-        self.result.push(self.current_line.clone());
-        self.current_line.clear();
+    // This is the exact gap chunk1-3 had to fix in chunk0-6's first cut: sorting
+    // accumulated ranges isn't enough, since two content nodes matched for the same
+    // combined injection can produce overlapping (or merely adjacent) ranges, which
+    // `set_included_ranges` rejects unless they're merged first.
+    #[test]
+    fn merge_ranges_combines_overlapping_and_adjacent_ranges() {
+        assert_eq!(
+            merge_ranges(vec![range(10, 20), range(0, 5), range(15, 25)]),
+            vec![range(0, 5), range(10, 25)],
+        );
+        assert_eq!(merge_ranges(vec![range(0, 5), range(5, 10)]), vec![range(0, 10)]);
+        assert_eq!(
+            merge_ranges(vec![range(0, 5), range(6, 10)]),
+            vec![range(0, 5), range(6, 10)],
+        );
     }
 
-    fn add_text(&mut self, src: &str, scopes: &Vec<Highlight>) {
-        let mut multiline = false;
-        for line in src.split('\n') {
-            let line = line.trim_end_matches('\r');
-            if multiline {
-                scopes.iter().for_each(|_| self.end_scope());
-                self.finish_line();
-                scopes
-                    .iter()
-                    .for_each(|highlight| self.start_scope(*highlight));
+    // Covers the other half of the same gap at the level a property sheet author
+    // actually uses: a `combined: true` injection matched against two sibling content
+    // nodes must materialize as a single layer once the parent's subtree is fully
+    // walked, not one layer per match.
+    #[test]
+    fn combined_injection_merges_sibling_content_nodes_into_one_layer() {
+        const HTML_COMBINED_SCRIPT_INJECTION_JSON: &str = r#"[
+            {
+                "selector": "script_element",
+                "properties": {
+                    "injection-language": "javascript",
+                    "injection-content": { "name": "child", "args": [{ "name": "this" }, 1] },
+                    "injection-combined": true
+                }
             }
-            write!(&mut self.current_line, "{}", escape::Escape(line)).unwrap();
-            multiline = true;
-        }
+        ]"#;
+
+        let html = tree_sitter_html::language();
+        let js = tree_sitter_javascript::language();
+        let html_sheet = load_property_sheet(html, HTML_COMBINED_SCRIPT_INJECTION_JSON).unwrap();
+        let js_sheet = empty_sheet(js);
+        let callback = |name: &str| {
+            if name == "javascript" {
+                Some((js, &js_sheet))
+            } else {
+                None
+            }
+        };
+
+        let source: &[u8] = b"<script>1</script><script>2</script>";
+        let pool = run_to_end(
+            Highlighter::new(source, html, &html_sheet, callback, &[], &[], &[], None, None)
+                .unwrap(),
+        );
+        assert_eq!(
+            pool.layers.len(),
+            2,
+            "the two script_element content nodes should materialize as a single \
+             combined javascript layer, not one layer per match, so the pool should hold \
+             just the html root layer plus that one combined layer"
+        );
+    }
+
+    // `injection-combined: []` (and `injection-includes-children: []`) are valid JSON,
+    // but used to panic while loading the property sheet: the indexing needed to
+    // back-fill a short list via `resize(_, list[0])` doesn't check the list is
+    // non-empty first. An empty list should behave like an absent key instead.
+    #[test]
+    fn empty_injection_combined_list_does_not_panic_loading_the_property_sheet() {
+        const EMPTY_COMBINED_LIST_JSON: &str = r#"[
+            {
+                "selector": "script_element",
+                "properties": {
+                    "injection-language": "javascript",
+                    "injection-content": { "name": "child", "args": [{ "name": "this" }, 1] },
+                    "injection-combined": [],
+                    "injection-includes-children": []
+                }
+            }
+        ]"#;
+
+        let html = tree_sitter_html::language();
+        load_property_sheet(html, EMPTY_COMBINED_LIST_JSON)
+            .expect("an empty injection-combined/injection-includes-children list should load, not panic");
+    }
+
+    // `resolve_highlight` is the taxonomy every other consumer now depends on (it
+    // replaced the fixed `Highlight` enum), so its longest-dot-prefix fallback behavior
+    // needs direct coverage, not just incidental exercise through other tests.
+    #[test]
+    fn resolve_highlight_falls_back_to_shorter_dot_prefixes() {
+        let names: Vec<String> = vec!["variable".to_string(), "keyword".to_string()];
+
+        // An exact match wins outright.
+        assert_eq!(resolve_highlight(&names, "keyword"), Some(Highlight(1)));
+
+        // `variable.parameter` itself isn't configured, so it falls back to the shorter
+        // `variable` prefix.
+        assert_eq!(resolve_highlight(&names, "variable.parameter"), Some(Highlight(0)));
+
+        // A name whose intermediate dotted prefixes aren't configured either falls back
+        // the same way, as many levels as it takes.
+        assert_eq!(
+            resolve_highlight(&names, "variable.parameter.builtin"),
+            Some(Highlight(0)),
+        );
+
+        // No prefix of the scope matches any configured name at all.
+        assert_eq!(resolve_highlight(&names, "function.method"), None);
+
+        // The more specific name wins when both it and a shorter prefix are configured.
+        let names: Vec<String> = vec!["variable".to_string(), "variable.parameter".to_string()];
+        assert_eq!(
+            resolve_highlight(&names, "variable.parameter"),
+            Some(Highlight(1)),
+        );
+    }
+
+    #[test]
+    fn resolve_injection_language_matches_regexes_in_order_case_insensitively() {
+        let regexes = vec![
+            (regex::Regex::new("^js$|^javascript$").unwrap(), "javascript".to_string()),
+            (regex::Regex::new("^ts$|^typescript$").unwrap(), "typescript".to_string()),
+        ];
+
+        // The raw string is lowercased before matching, so its case doesn't matter.
+        assert_eq!(resolve_injection_language("JS", &regexes), "javascript");
+        assert_eq!(resolve_injection_language("TypeScript", &regexes), "typescript");
+
+        // The first matching regex wins, even if a later one would also match.
+        let ambiguous = vec![
+            (regex::Regex::new("^a").unwrap(), "first".to_string()),
+            (regex::Regex::new("^ab").unwrap(), "second".to_string()),
+        ];
+        assert_eq!(resolve_injection_language("abc", &ambiguous), "first");
+
+        // No regex matches: falls back to the literal raw string, unchanged.
+        assert_eq!(resolve_injection_language("dunno", &regexes), "dunno");
+    }
+
+    #[test]
+    fn detect_injection_language_from_content_uses_first_match_or_none() {
+        let regexes = vec![
+            (regex::Regex::new(r"^#!.*\bsh\b").unwrap(), "bash".to_string()),
+            (regex::Regex::new(r"^\{").unwrap(), "json".to_string()),
+        ];
+
+        assert_eq!(
+            detect_injection_language_from_content(&regexes, "#!/bin/sh\necho hi"),
+            Some("bash".to_string()),
+        );
+        assert_eq!(
+            detect_injection_language_from_content(&regexes, "{\"a\": 1}"),
+            Some("json".to_string()),
+        );
+        assert_eq!(
+            detect_injection_language_from_content(&regexes, "plain text, no markers"),
+            None,
+        );
+    }
+
+    // Exercises the deadline check in `Iterator::next` without relying on a real sleep
+    // (which would be slow and flaky): a `Duration::ZERO` timeout makes the deadline
+    // equal to the instant the `Highlighter` is constructed (after its initial parse), so
+    // by the time enough events have been produced to trip `CANCELLATION_CHECK_INTERVAL`,
+    // `Instant::now()` is guaranteed to have moved past it.
+    #[test]
+    fn highlighter_reports_timeout_once_deadline_has_passed() {
+        const NUMBER_HIGHLIGHT_JSON: &str = r#"[
+            { "selector": "number", "properties": { "highlight": "number" } }
+        ]"#;
+
+        let json = tree_sitter_json::language();
+        let sheet = load_property_sheet(json, NUMBER_HIGHLIGHT_JSON).unwrap();
+        let recognized_names: Vec<String> = vec!["number".to_string()];
+
+        // Enough numbers that the HighlightStart/Source/HighlightEnd events they
+        // produce push the iterator well past `CANCELLATION_CHECK_INTERVAL` calls to
+        // `next`, guaranteeing the deadline check actually runs before the document is
+        // fully consumed.
+        let source = format!(
+            "[{}]",
+            (0..100).map(|n| n.to_string()).collect::<Vec<_>>().join(",")
+        );
+        let source = source.as_bytes();
+
+        let callback = |_: &str| None;
+        let mut highlighter = Highlighter::new(
+            source,
+            json,
+            &sheet,
+            callback,
+            &[],
+            &[],
+            &recognized_names,
+            None,
+            Some(Duration::ZERO),
+        )
+        .unwrap();
+
+        let saw_timeout = (&mut highlighter).any(|event| matches!(event, Err(Error::Timeout)));
+        assert!(saw_timeout, "expected a Duration::ZERO timeout to surface Error::Timeout");
+    }
+
+    #[test]
+    fn highlight_ranges_resolves_nested_scopes_to_the_innermost_highlight() {
+        let events: Vec<Result<HighlightEvent, Error>> = vec![
+            Ok(HighlightEvent::HighlightStart(Highlight(0))), // string
+            Ok(HighlightEvent::Source("'he".into())),
+            Ok(HighlightEvent::HighlightStart(Highlight(1))), // escape, nested in string
+            Ok(HighlightEvent::Source("llo".into())),
+            Ok(HighlightEvent::HighlightEnd), // end escape
+            Ok(HighlightEvent::Source("'".into())),
+            Ok(HighlightEvent::HighlightEnd), // end string
+            Ok(HighlightEvent::Source(" ".into())), // unhighlighted, must be omitted
+            Ok(HighlightEvent::HighlightStart(Highlight(2))), // keyword
+            Ok(HighlightEvent::Source("end".into())),
+            Ok(HighlightEvent::HighlightEnd),
+        ];
+
+        let ranges = highlight_ranges(events).unwrap();
+
+        assert_eq!(
+            ranges,
+            vec![
+                (0..3, Highlight(0)),  // 'he -> string
+                (3..6, Highlight(1)),  // llo -> nested escape wins over string
+                (6..7, Highlight(0)),  // ' -> back to string once the escape ends
+                (8..11, Highlight(2)), // end -> keyword; the space at 7..8 is omitted
+            ],
+        );
     }
 }